@@ -11,12 +11,17 @@ use lazy_static::lazy_static;
 
 use crate::common::client_to_server::ClientToServerMessage;
 use crate::common::server_to_client::ServerToClientMessage;
+use crate::net_settings::{HANDSHAKE_TIMEOUT, TCP_NODELAY};
+use crate::trace::trace_message;
 
 lazy_static! {
     pub static ref INCOMING_MESSAGE_QUEUE: Arc<ArrayQueue<ServerToClientMessage>> =
         Arc::new(ArrayQueue::new(1000));
     pub static ref OUTBOUND_MESSAGE_QUEUE: Arc<ArrayQueue<ClientToServerMessage>> =
         Arc::new(ArrayQueue::new(1000));
+    // position spam and the like, always sent after OUTBOUND_MESSAGE_QUEUE is empty
+    pub static ref DROPPABLE_OUTBOUND_MESSAGE_QUEUE: Arc<ArrayQueue<ClientToServerMessage>> =
+        Arc::new(ArrayQueue::new(16));
     pub static ref SERVER_DISCONNECTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     pub static ref CLIENT_ID: Arc<AtomicU32> = Arc::new(AtomicU32::new(0));
 }
@@ -42,15 +47,46 @@ lazy_static! {
 
 pub async fn disconnect_from_server() {}
 
+/// Positions are superseded by the next one, anything else must arrive.
+pub fn is_droppable(message: &ClientToServerMessage) -> bool {
+    matches!(message, ClientToServerMessage::Position { .. })
+}
+
+/// Queues a message for the tx task. When backed up, droppable messages lose
+/// their oldest entry so they never crowd out chat and control messages.
+/// False if the message had to be dropped.
+pub fn queue_outbound_message(message: ClientToServerMessage) -> bool {
+    if is_droppable(&message) {
+        // evicts the oldest one when full, it was stale anyway
+        DROPPABLE_OUTBOUND_MESSAGE_QUEUE.force_push(message);
+        return true;
+    }
+    if OUTBOUND_MESSAGE_QUEUE.push(message).is_err() {
+        eprintln!("Outbound message queue full: dropping message");
+        return false;
+    }
+    true
+}
+
+/// Reliable messages first, droppable ones only once those are all out.
+pub fn next_outbound_message() -> Option<ClientToServerMessage> {
+    OUTBOUND_MESSAGE_QUEUE
+        .pop()
+        .or_else(|| DROPPABLE_OUTBOUND_MESSAGE_QUEUE.pop())
+}
+
 ////////////////////////    CLIENT RX/TX TASKS    ////////////////////////
 
 pub async fn init_connection() -> tokio::io::Result<()> {
     let stream = TcpStream::connect(SERVER_ADDR).await?;
+    stream.set_nodelay(TCP_NODELAY)?;
     let (mut read_half, write_half) = stream.into_split();
 
     // Receive the assigned ID from the server
     let mut id_buffer = [0u8; 4];
-    read_half.read_exact(&mut id_buffer).await?;
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, read_half.read_exact(&mut id_buffer))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no client id from server"))??;
     let client_id = u32::from_be_bytes(id_buffer);
     CLIENT_ID.store(client_id, Ordering::SeqCst);
 
@@ -74,6 +110,7 @@ pub async fn receive_incoming_messages(
         let result: Result<ServerToClientMessage, _> = bincode::deserialize(&buffer[..nbytes]);
         match result {
             Ok(message) => {
+                trace_message("rx", id, &message);
                 if INCOMING_MESSAGE_QUEUE.push(message).is_err() {
                     eprintln!("Inbound message queue full: dropping message from {}", id);
                 }
@@ -97,8 +134,9 @@ pub async fn transmit_outbound_messages(
             return Ok(());
         }
 
-        // transmit any outbound messages
-        if let Some(message) = OUTBOUND_MESSAGE_QUEUE.pop() {
+        // transmit any outbound messages, reliable ones first
+        if let Some(message) = next_outbound_message() {
+            trace_message("tx", CLIENT_ID.load(Ordering::Relaxed), &message);
             match bincode::serialize(&message) {
                 Ok(binary_message) => {
                     socket_write_half.write_all(&binary_message).await?;
@@ -112,3 +150,37 @@ pub async fn transmit_outbound_messages(
         tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_goes_out_ahead_of_a_position_flood() {
+        while next_outbound_message().is_some() {}
+
+        for i in 0..1000 {
+            let position = ClientToServerMessage::Position {
+                x: i as f32,
+                y: 0.0,
+            };
+            assert!(queue_outbound_message(position));
+        }
+        let chat = ClientToServerMessage::ChatMessage {
+            message: "still here".to_string(),
+        };
+        assert!(queue_outbound_message(chat));
+
+        assert!(matches!(
+            next_outbound_message(),
+            Some(ClientToServerMessage::ChatMessage { message }) if message == "still here"
+        ));
+        // only the newest positions survive the flood
+        let positions: Vec<_> = std::iter::from_fn(next_outbound_message).collect();
+        assert_eq!(positions.len(), DROPPABLE_OUTBOUND_MESSAGE_QUEUE.capacity());
+        assert!(matches!(
+            positions.last(),
+            Some(ClientToServerMessage::Position { x, .. }) if *x == 999.0
+        ));
+    }
+}
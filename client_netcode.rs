@@ -1,20 +1,177 @@
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, Instant};
+
+use crossbeam::queue::ArrayQueue;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+use crate::net_settings::{HANDSHAKE_TIMEOUT, TCP_NODELAY};
 use crate::protocol::{ClientMessage, Player};
 use crate::settings::SERVER_ADDR;
 use crate::sketch::ClientState;
+use crate::transport::{QueueTransport, Transport};
+
+const HANDSHAKE_RETRY_DELAY: Duration = Duration::from_secs(2);
+const PLAYER_UPDATE_INTERVAL: Duration = Duration::from_millis(32);
+const OUTBOUND_QUEUE_SIZE: usize = 64;
+/// Record every message we send to this file, for replaying at the server later.
+pub const OUTGOING_LOG_PATH: Option<&str> = None;
+
+/// One line per sent message: milliseconds since connecting, a tab, then the json.
+pub struct OutgoingLog {
+    started: Instant,
+    writer: BufWriter<File>,
+}
+
+impl OutgoingLog {
+    pub fn create(path: &str) -> io::Result<Self> {
+        Ok(Self {
+            started: Instant::now(),
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn record(&mut self, json_message: &str) -> io::Result<()> {
+        let millis = self.started.elapsed().as_millis();
+        writeln!(self.writer, "{}\t{}", millis, json_message)?;
+        self.writer.flush()
+    }
+}
+
+/// Re-sends a recorded session against a live server at the recorded cadence.
+/// The server hands out a fresh id, so ids inside the messages are the recorded ones.
+pub async fn replay_outgoing_log(path: &str) -> io::Result<()> {
+    let (mut stream, player_id) = connect_and_handshake().await?;
+    println!("Replaying {} as player {}", path, player_id);
+
+    let started = Instant::now();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let Some((millis, json_message)) = line.split_once('\t') else {
+            eprintln!("Skipping malformed replay line: {}", line);
+            continue;
+        };
+        let Ok(millis) = millis.parse::<u64>() else {
+            eprintln!("Skipping malformed replay line: {}", line);
+            continue;
+        };
+
+        let send_at = Duration::from_millis(millis);
+        if let Some(wait) = send_at.checked_sub(started.elapsed()) {
+            tokio::time::sleep(wait).await;
+        }
+        stream.write_all(json_message.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn connect_and_handshake() -> io::Result<(TcpStream, u32)> {
+    let mut stream = TcpStream::connect(SERVER_ADDR).await?;
+    stream.set_nodelay(TCP_NODELAY)?;
+
+    let mut id_buffer = [0u8; 4];
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut id_buffer))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no player id from server"))??;
+    Ok((stream, u32::from_be_bytes(id_buffer)))
+}
+
+/// What the sketch client talks through. The server sends nothing back after the id.
+pub type SketchTransport = QueueTransport<ClientMessage, Infallible>;
+
+/// Handshakes, then hands the socket to a task that writes out whatever gets sent.
+async fn connect_transport() -> io::Result<(SketchTransport, u32)> {
+    let (stream, player_id) = connect_and_handshake().await?;
+    let outgoing_log = OUTGOING_LOG_PATH.and_then(|path| {
+        OutgoingLog::create(path)
+            .map_err(|e| eprintln!("Couldn't create outgoing log {}: {}", path, e))
+            .ok()
+    });
+
+    let transport = QueueTransport {
+        outbound: Arc::new(ArrayQueue::new(OUTBOUND_QUEUE_SIZE)),
+        incoming: Arc::new(ArrayQueue::new(1)),
+        disconnected: Arc::new(AtomicBool::new(false)),
+    };
+    tokio::spawn(transmit_json_messages(
+        stream,
+        transport.outbound.clone(),
+        transport.disconnected.clone(),
+        outgoing_log,
+    ));
+    Ok((transport, player_id))
+}
+
+async fn transmit_json_messages(
+    mut stream: TcpStream,
+    outbound: Arc<ArrayQueue<ClientMessage>>,
+    disconnected: Arc<AtomicBool>,
+    mut outgoing_log: Option<OutgoingLog>,
+) {
+    while !disconnected.load(Ordering::SeqCst) {
+        while let Some(message) = outbound.pop() {
+            let json_message = serde_json::to_string(&message).unwrap();
+            if let Err(e) = stream.write_all(json_message.as_bytes()).await {
+                eprintln!("Lost connection to server: {}", e);
+                disconnected.store(true, Ordering::SeqCst);
+                return;
+            }
+            if let Some(log) = outgoing_log.as_mut() {
+                if let Err(e) = log.record(&json_message) {
+                    eprintln!("Couldn't write outgoing log, stopping it: {}", e);
+                    outgoing_log = None;
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Sends our player's position every tick until the transport disconnects.
+pub async fn send_player_updates<T: Transport<Outbound = ClientMessage>>(
+    transport: &T,
+    state: &Mutex<ClientState>,
+    player_id: u32,
+) {
+    while !transport.is_disconnected() {
+        let player = {
+            let locked_state = state.lock().unwrap();
+            locked_state
+                .players
+                .get(&player_id)
+                .map(|player| (player.pos, player.vel))
+        }; // Lock is released here.
+
+        if let Some((pos, vel)) = player {
+            let message = ClientMessage::PlayerUpdate {
+                id: player_id,
+                pos,
+                vel,
+            };
+            // the next update supersedes this one anyway
+            transport.send(message);
+        }
+        tokio::time::sleep(PLAYER_UPDATE_INTERVAL).await;
+    }
+}
 
 pub fn spawn_networking_task(state: Arc<Mutex<ClientState>>) {
     std::thread::spawn(move || {
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async {
-            let mut stream = TcpStream::connect(SERVER_ADDR).await.unwrap();
-
-            let mut id_buffer = [0u8; 4];
-            stream.read_exact(&mut id_buffer).await.unwrap();
-            let player_id = u32::from_be_bytes(id_buffer);
+            let (transport, player_id) = loop {
+                match connect_transport().await {
+                    Ok(connection) => break connection,
+                    Err(e) => {
+                        eprintln!("Handshake with server failed: {}, retrying", e);
+                        tokio::time::sleep(HANDSHAKE_RETRY_DELAY).await;
+                    }
+                }
+            };
             println!("Player id: {}", player_id);
 
             {
@@ -27,25 +184,58 @@ pub fn spawn_networking_task(state: Arc<Mutex<ClientState>>) {
                     .insert(player_id, Player::new(player_id));
             }
 
-            loop {
-                let (_, pos, vel) = {
-                    let locked_state = state.lock().unwrap();
-                    if let Some(player) = locked_state.players.get(&player_id) {
-                        (player.id, player.pos, player.vel)
-                    } else {
-                        continue;
-                    }
-                }; // Lock is released here.
-
-                let message = ClientMessage::PlayerUpdate {
-                    id: player_id,
-                    pos,
-                    vel,
-                };
-                let json_message = serde_json::to_string(&message).unwrap();
-                stream.write_all(json_message.as_bytes()).await.unwrap();
-                tokio::time::sleep(tokio::time::Duration::from_millis(32)).await;
-            }
+            send_player_updates(&transport, &state, player_id).await;
         });
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_transport() -> SketchTransport {
+        QueueTransport {
+            outbound: Arc::new(ArrayQueue::new(OUTBOUND_QUEUE_SIZE)),
+            incoming: Arc::new(ArrayQueue::new(1)),
+            disconnected: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    #[tokio::test]
+    async fn player_updates_go_out_through_the_transport() {
+        let transport = memory_transport();
+        let state = Mutex::new(ClientState::new());
+        state.lock().unwrap().players.insert(7, Player::new(7));
+
+        let ran_for = PLAYER_UPDATE_INTERVAL * 3;
+        let _ = tokio::time::timeout(ran_for, send_player_updates(&transport, &state, 7)).await;
+
+        let sent: Vec<ClientMessage> = std::iter::from_fn(|| transport.outbound.pop()).collect();
+        assert!(!sent.is_empty());
+        assert!(sent
+            .iter()
+            .all(|message| matches!(message, ClientMessage::PlayerUpdate { id: 7, .. })));
+    }
+
+    #[tokio::test]
+    async fn nothing_is_sent_before_the_player_exists() {
+        let transport = memory_transport();
+        let state = Mutex::new(ClientState::new());
+
+        let ran_for = PLAYER_UPDATE_INTERVAL * 3;
+        let _ = tokio::time::timeout(ran_for, send_player_updates(&transport, &state, 7)).await;
+        assert!(transport.outbound.is_empty());
+    }
+
+    #[tokio::test]
+    async fn updates_stop_once_disconnected() {
+        let transport = memory_transport();
+        let state = Mutex::new(ClientState::new());
+        state.lock().unwrap().players.insert(7, Player::new(7));
+        transport.disconnected.store(true, Ordering::SeqCst);
+
+        // returns on its own instead of looping forever
+        send_player_updates(&transport, &state, 7).await;
+        assert!(transport.outbound.is_empty());
+    }
+}
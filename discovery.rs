@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use tokio::io;
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant};
+
+use crate::server_connection_handling::CLIENT_OUTBOUND_MAILBOXES;
+
+////////////////////////    LAN SERVER DISCOVERY    ////////////////////////
+// clients broadcast DISCOVERY_REQUEST on DISCOVERY_PORT,
+// every server on the lan answers with a DiscoveryReply
+
+pub const DISCOVERY_PORT: u16 = 8081;
+const DISCOVERY_REQUEST: &[u8] = b"rusttcpgamedev?";
+const DISCOVERY_WINDOW: Duration = Duration::from_millis(750);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DiscoveryReply {
+    name: String,
+    game_port: u16,
+    players: u32,
+    max_players: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    pub name: String,
+    pub addr: SocketAddr,
+    pub players: u32,
+    pub max_players: u32,
+}
+
+/// Answers discovery broadcasts until the socket errors. Spawn it next to the tcp listener.
+pub async fn run_discovery_responder(
+    name: String,
+    game_port: u16,
+    max_players: u32,
+) -> io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).await?;
+    let mut buffer = [0; 64];
+    loop {
+        let (nbytes, from) = socket.recv_from(&mut buffer).await?;
+        if &buffer[..nbytes] != DISCOVERY_REQUEST {
+            continue;
+        }
+
+        let players = CLIENT_OUTBOUND_MAILBOXES.read().await.len() as u32;
+        let reply = DiscoveryReply {
+            name: name.clone(),
+            game_port,
+            players,
+            max_players,
+        };
+        match bincode::serialize(&reply) {
+            Ok(binary_reply) => {
+                if let Err(e) = socket.send_to(&binary_reply, from).await {
+                    eprintln!("Error answering discovery from {}: {:?}", from, e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error serializing discovery reply: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Broadcasts on the lan and collects whoever answers within the discovery window.
+pub async fn discover_servers() -> io::Result<Vec<ServerInfo>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    socket
+        .send_to(DISCOVERY_REQUEST, (Ipv4Addr::BROADCAST, DISCOVERY_PORT))
+        .await?;
+
+    // keyed by address so a server that answers twice only shows up once
+    let mut servers = HashMap::new();
+    let deadline = Instant::now() + DISCOVERY_WINDOW;
+    let mut buffer = [0; 1024];
+    loop {
+        let received = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await;
+        let (nbytes, from) = match received {
+            Ok(result) => result?,
+            Err(_) => break, // window is over
+        };
+
+        match bincode::deserialize::<DiscoveryReply>(&buffer[..nbytes]) {
+            Ok(reply) => {
+                let addr = SocketAddr::new(from.ip(), reply.game_port);
+                servers.insert(
+                    addr,
+                    ServerInfo {
+                        name: reply.name,
+                        addr,
+                        players: reply.players,
+                        max_players: reply.max_players,
+                    },
+                );
+            }
+            Err(e) => {
+                eprintln!("Ignoring bad discovery reply from {}: {:?}", from, e);
+            }
+        }
+    }
+    Ok(servers.into_values().collect())
+}
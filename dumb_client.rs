@@ -4,11 +4,18 @@ use std::sync::Arc;
 use crossbeam::queue::ArrayQueue;
 use glam::Vec2;
 use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 
 const SERVER_ADDR: &str = "127.0.0.1:8080";
+// this many garbage datagrams in a row means whoever is on the other end isn't our server
+const MAX_CONSECUTIVE_PARSE_FAILURES: u32 = 20;
 use lazy_static::lazy_static;
 use uuid::Uuid;
 
+mod trace;
+use trace::trace_message;
+
 lazy_static! {
     pub static ref INCOMING_MESSAGE_QUEUE: Arc<ArrayQueue<ServerToClientMessage>> =
         Arc::new(ArrayQueue::new(1000));
@@ -16,6 +23,8 @@ lazy_static! {
         Arc::new(ArrayQueue::new(1000));
     pub static ref SERVER_DISCONNECTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     pub static ref CLIENT_UUID: Uuid = Uuid::new_v4();
+    // flips to true once, every network task watches it and winds down
+    pub static ref SHUTDOWN: watch::Sender<bool> = watch::channel(false).0;
 }
 
 pub struct State {
@@ -40,13 +49,15 @@ impl Default for State {
 
 #[tokio::main]
 async fn main() -> tokio::io::Result<()> {
-    let result = init_connection().await;
-    if let Err(e) = result {
-        eprintln!("Error connecting to server: {:?}", e);
-        return Ok(());
-    }
+    let network_tasks = match init_connection().await {
+        Ok(network_tasks) => network_tasks,
+        Err(e) => {
+            eprintln!("Error connecting to server: {:?}", e);
+            return Ok(());
+        }
+    };
     let mut state = State::new();
-    loop {
+    while !SERVER_DISCONNECTED.load(Ordering::SeqCst) {
         // lets send a chat message
         let message = ClientToServerMessage::ChatMessage {
             message: "Hey Man!".to_string(),
@@ -57,8 +68,24 @@ async fn main() -> tokio::io::Result<()> {
 
         process_message_queue();
         step(&mut state);
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(1)) => {}
+            _ = tokio::signal::ctrl_c() => {
+                println!("shutting down");
+                break;
+            }
+        }
+    }
+
+    disconnect_from_server().await;
+    for task in network_tasks {
+        match task.await {
+            Ok(Err(e)) => eprintln!("Network task failed: {:?}", e),
+            Err(e) => eprintln!("Network task panicked: {:?}", e),
+            Ok(Ok(())) => {}
+        }
     }
+    Ok(())
 }
 
 fn step(state: &mut State) {
@@ -68,9 +95,17 @@ fn step(state: &mut State) {
 pub fn process_message_queue() {
     while let Some(message) = INCOMING_MESSAGE_QUEUE.pop() {
         match message {
-            ServerToClientMessage::Welcome { server_message } => {
+            ServerToClientMessage::Welcome {
+                server_message,
+                server_name,
+                server_version,
+            } => {
+                println!("Connected to {} v{}", server_name, server_version);
                 println!("Server says: {}", server_message);
             }
+            ServerToClientMessage::SystemMessage { message } => {
+                println!("*** {} ***", message);
+            }
             ServerToClientMessage::ClientJoined { id } => {
                 println!("Player {} joined", id);
             }
@@ -80,6 +115,22 @@ pub fn process_message_queue() {
             ServerToClientMessage::ChatMessage { from, message } => {
                 println!("{} says: {}", from, message);
             }
+            ServerToClientMessage::Relayed { from, payload } => {
+                println!("{} relayed {} bytes", from, payload.len());
+            }
+            ServerToClientMessage::PlayerLagging { id, lagging } => {
+                if lagging {
+                    println!("Player {} is lagging", id);
+                } else {
+                    println!("Player {} caught up", id);
+                }
+            }
+            ServerToClientMessage::Effect { kind, x, y } => {
+                println!("Effect {} at ({}, {})", kind, x, y);
+            }
+            ServerToClientMessage::Doodle { id, strokes } => {
+                println!("{} drew a doodle with {} points", id, strokes.len());
+            }
             _ => {
                 eprintln!("Unknown message type");
             }
@@ -87,11 +138,14 @@ pub fn process_message_queue() {
     }
 }
 
-pub async fn disconnect_from_server() {}
+/// Tells the network tasks to say goodbye to the server and stop.
+pub async fn disconnect_from_server() {
+    SHUTDOWN.send_replace(true);
+}
 
 ////////////////////////    CLIENT RX/TX TASKS    ////////////////////////
 
-pub async fn init_connection() -> tokio::io::Result<()> {
+pub async fn init_connection() -> tokio::io::Result<[JoinHandle<io::Result<()>>; 2]> {
     println!("connecting");
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
     socket.connect(SERVER_ADDR).await?;
@@ -100,24 +154,40 @@ pub async fn init_connection() -> tokio::io::Result<()> {
     let a_socket = Arc::new(socket);
 
     println!("spawning network tasks");
-    tokio::spawn(receive_incoming_messages(a_socket.clone()));
-    tokio::spawn(transmit_outbound_messages(a_socket.clone()));
-    Ok(())
+    let rx = tokio::spawn(receive_incoming_messages(a_socket.clone()));
+    let tx = tokio::spawn(transmit_outbound_messages(a_socket.clone()));
+    Ok([rx, tx])
 }
 
 pub async fn receive_incoming_messages(socket: Arc<UdpSocket>) -> io::Result<()> {
     let mut buffer = [0; 1024];
+    let mut consecutive_parse_failures = 0;
+    let mut shutdown = SHUTDOWN.subscribe();
     loop {
-        let nbytes = socket.recv(&mut buffer).await?;
+        let nbytes = tokio::select! {
+            result = socket.recv(&mut buffer) => result?,
+            _ = shutdown.wait_for(|&stop| stop) => return Ok(()),
+        };
         let result: Result<ServerToClientMessage, _> = bincode::deserialize(&buffer[..nbytes]);
         match result {
             Ok(message) => {
+                consecutive_parse_failures = 0;
+                trace_message("rx", *CLIENT_UUID, &message);
                 if INCOMING_MESSAGE_QUEUE.push(message).is_err() {
                     eprintln!("Inbound message queue full: dropping message");
                 }
             }
             Err(e) => {
                 eprintln!("Error parsing client data: {:?}", e);
+                consecutive_parse_failures += 1;
+                if consecutive_parse_failures >= MAX_CONSECUTIVE_PARSE_FAILURES {
+                    eprintln!(
+                        "{} unparseable messages in a row, not a compatible server: disconnecting",
+                        consecutive_parse_failures
+                    );
+                    SERVER_DISCONNECTED.store(true, Ordering::SeqCst);
+                    return Ok(());
+                }
             }
         }
 
@@ -126,26 +196,44 @@ pub async fn receive_incoming_messages(socket: Arc<UdpSocket>) -> io::Result<()>
 }
 
 pub async fn transmit_outbound_messages(socket: Arc<UdpSocket>) -> io::Result<()> {
+    let mut shutdown = SHUTDOWN.subscribe();
     loop {
         // check for disconnect message from rx task
         if SERVER_DISCONNECTED.load(Ordering::SeqCst) {
-            disconnect_from_server().await; // TODO: implement cleanup
-            return Ok(());
+            disconnect_from_server().await;
+        }
+        if *shutdown.borrow() {
+            break;
         }
 
         // transmit any outbound messages
         if let Some(message) = OUTBOUND_MESSAGE_QUEUE.pop() {
-            println!("Sending message: {:?}", message);
-            match bincode::serialize(&message) {
-                Ok(binary_message) => {
-                    socket.send(&binary_message).await?;
-                }
-                Err(e) => {
-                    eprintln!("Error serializing message: {:?}", e);
-                }
-            }
+            send_message(&socket, &message).await?;
         }
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_millis(100)) => {}
+            _ = shutdown.changed() => {}
+        }
     }
+
+    // flush whatever is left, then say goodbye so the server doesn't wait on a timeout
+    while let Some(message) = OUTBOUND_MESSAGE_QUEUE.pop() {
+        send_message(&socket, &message).await?;
+    }
+    send_message(&socket, &ClientToServerMessage::Disconnect).await?;
+    Ok(())
+}
+
+async fn send_message(socket: &UdpSocket, message: &ClientToServerMessage) -> io::Result<()> {
+    trace_message("tx", *CLIENT_UUID, message);
+    match bincode::serialize(message) {
+        Ok(binary_message) => {
+            socket.send(&binary_message).await?;
+        }
+        Err(e) => {
+            eprintln!("Error serializing message: {:?}", e);
+        }
+    }
+    Ok(())
 }
@@ -4,7 +4,7 @@ use std::sync::Arc;
 use common::client_to_server::ClientToServerMessage;
 use crossbeam::queue::ArrayQueue;
 use glam::Vec2;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
@@ -15,6 +15,11 @@ use tokio::sync::RwLock;
 use crate::common::server_to_client::ServerToClientMessage;
 
 mod common;
+mod net_settings;
+mod trace;
+use net_settings::{HANDSHAKE_TIMEOUT, TCP_NODELAY};
+use trace::trace_message;
+
 lazy_static! {
     pub static ref INCOMING_MESSAGE_QUEUE: Arc<ArrayQueue<ServerToClientMessage>> =
         Arc::new(ArrayQueue::new(1000));
@@ -73,9 +78,17 @@ fn step(state: &mut State) {
 pub fn process_message_queue() {
     while let Some(message) = INCOMING_MESSAGE_QUEUE.pop() {
         match message {
-            ServerToClientMessage::Welcome { server_message } => {
+            ServerToClientMessage::Welcome {
+                server_message,
+                server_name,
+                server_version,
+            } => {
+                println!("Connected to {} v{}", server_name, server_version);
                 println!("Server says: {}", server_message);
             }
+            ServerToClientMessage::SystemMessage { message } => {
+                println!("*** {} ***", message);
+            }
             ServerToClientMessage::PlayerJoined { id } => {
                 println!("Player {} joined", id);
             }
@@ -85,6 +98,22 @@ pub fn process_message_queue() {
             ServerToClientMessage::ChatMessage { from, message } => {
                 println!("{} says: {}", from, message);
             }
+            ServerToClientMessage::Relayed { from, payload } => {
+                println!("{} relayed {} bytes", from, payload.len());
+            }
+            ServerToClientMessage::PlayerLagging { id, lagging } => {
+                if lagging {
+                    println!("Player {} is lagging", id);
+                } else {
+                    println!("Player {} caught up", id);
+                }
+            }
+            ServerToClientMessage::Effect { kind, x, y } => {
+                println!("Effect {} at ({}, {})", kind, x, y);
+            }
+            ServerToClientMessage::Doodle { id, strokes } => {
+                println!("{} drew a doodle with {} points", id, strokes.len());
+            }
             _ => {
                 eprintln!("Unknown message type");
             }
@@ -98,15 +127,18 @@ pub async fn disconnect_from_server() {}
 
 pub async fn init_connection() -> tokio::io::Result<()> {
     let stream = TcpStream::connect(SERVER_ADDR).await?;
+    stream.set_nodelay(TCP_NODELAY)?;
     let (mut read_half, write_half) = stream.into_split();
 
     // Receive the assigned ID from the server
     let mut id_buffer = [0u8; 4];
-    read_half.read_exact(&mut id_buffer).await?;
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, read_half.read_exact(&mut id_buffer))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no client id from server"))??;
     let client_id = u32::from_be_bytes(id_buffer);
 
     tokio::spawn(receive_incoming_messages(client_id, read_half));
-    tokio::spawn(transmit_outbound_messages(write_half));
+    tokio::spawn(transmit_outbound_messages(client_id, write_half));
     Ok(())
 }
 
@@ -125,6 +157,7 @@ pub async fn receive_incoming_messages(
         let result: Result<ServerToClientMessage, _> = bincode::deserialize(&buffer[..nbytes]);
         match result {
             Ok(message) => {
+                trace_message("rx", id, &message);
                 if INCOMING_MESSAGE_QUEUE.push(message).is_err() {
                     eprintln!("Inbound message queue full: dropping message from {}", id);
                 }
@@ -139,6 +172,7 @@ pub async fn receive_incoming_messages(
 }
 
 pub async fn transmit_outbound_messages(
+    id: u32,
     mut socket_write_half: tokio::net::tcp::OwnedWriteHalf,
 ) -> io::Result<()> {
     loop {
@@ -150,6 +184,7 @@ pub async fn transmit_outbound_messages(
 
         // transmit any outbound messages
         if let Some(message) = OUTBOUND_MESSAGE_QUEUE.pop() {
+            trace_message("tx", id, &message);
             match bincode::serialize(&message) {
                 Ok(binary_message) => {
                     socket_write_half.write_all(&binary_message).await?;
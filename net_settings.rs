@@ -0,0 +1,7 @@
+use std::time::Duration;
+
+// connected but the server never sent our id, don't sit on "connecting..." forever
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// nagle holds small writes back up to ~40ms waiting to batch them, bad for position updates.
+// turn this off if bandwidth matters more than latency
+pub const TCP_NODELAY: bool = true;
@@ -6,10 +6,12 @@ use crossbeam::queue::ArrayQueue;
 use lazy_static::lazy_static;
 use std::{
     collections::HashMap,
+    net::IpAddr,
     sync::{
         atomic::{AtomicBool, AtomicU32, Ordering},
-        Arc,
+        Arc, Mutex,
     },
+    time::Instant,
 };
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt},
@@ -17,10 +19,48 @@ use tokio::{
     sync::RwLock,
 };
 
+use super::discovery;
+use super::net_settings::TCP_NODELAY;
 use super::settings::SERVER_ADDR;
+use super::trace::trace_message;
+use super::transport::{ClientTransport, QueueTransport};
 
 extern crate lazy_static;
 
+pub const SERVER_NAME: &str = "scratch server";
+// shown to every player right after they join, leave the file out to skip it
+pub const MOTD_PATH: &str = "motd.txt";
+// advertised to lan server browsers, nothing enforces it
+pub const MAX_PLAYERS: u32 = 64;
+// default, the MAX_CONNECTIONS_PER_IP env var overrides it.
+// load tests like spawn_wave connect hundreds of clients from one host and need it raised
+pub const MAX_CONNECTIONS_PER_IP: u32 = 4;
+// answer lan server browser broadcasts
+pub const LAN_DISCOVERY: bool = true;
+
+// flood protection, every inbound message spends tokens from its client's bucket
+pub const RATE_LIMIT_BURST: f32 = 40.0;
+pub const RATE_LIMIT_REFILL_PER_SECOND: f32 = 20.0;
+pub const CHAT_MESSAGE_COST: f32 = 5.0;
+pub const DEFAULT_MESSAGE_COST: f32 = 1.0;
+// relayed payloads are opaque to the server, keep them well under the read buffer
+pub const MAX_RELAY_PAYLOAD_BYTES: usize = 512;
+// each point is 8 bytes once encoded, this keeps a doodle inside one 1024 byte read
+pub const MAX_DOODLE_POINTS: usize = 100;
+// stroke points are offsets from the sender, a doodle has to fit in a box a few players wide
+pub const MAX_DOODLE_EXTENT: f32 = 32.0;
+// this many throttled messages in a row gets you kicked
+pub const FLOOD_KICK_THRESHOLD: u32 = 200;
+// raw inbound bytes per client per second, no matter how they're split into messages
+pub const MAX_INBOUND_BYTES_PER_SECOND: usize = 16 * 1024;
+// this many seconds in a row over the cap gets you kicked
+pub const BANDWIDTH_KICK_AFTER_WINDOWS: u32 = 5;
+// how long a new client can stay quiet before we announce their join anyway
+pub const JOIN_ANNOUNCE_GRACE: tokio::time::Duration = tokio::time::Duration::from_secs(1);
+// no messages from a client for this long and everyone else sees them as lagging
+pub const STALE_CLIENT_AFTER: tokio::time::Duration = tokio::time::Duration::from_millis(1500);
+pub const SLOW_CLIENT_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(5);
+
 lazy_static! {
     pub static ref INCOMING_MESSAGE_QUEUE: Arc<ArrayQueue<ClientToServerMessageBundle>> =
         Arc::new(ArrayQueue::new(1000));
@@ -29,6 +69,15 @@ lazy_static! {
         RwLock::new(HashMap::new());
     pub static ref CLIENT_DISCONNECTED: Arc<RwLock<HashMap<u32, Arc<AtomicBool>>>> =
         Arc::new(RwLock::new(HashMap::new()));
+    pub static ref MOTD: Option<String> = load_motd(MOTD_PATH);
+    // when each client last sent anything, and whether they're currently flagged as lagging
+    pub static ref CLIENT_LAST_HEARD: Mutex<HashMap<u32, (Instant, bool)>> =
+        Mutex::new(HashMap::new());
+    // connected clients whose join hasn't been broadcast yet, and when they connected
+    pub static ref PENDING_JOINS: Mutex<HashMap<u32, Instant>> = Mutex::new(HashMap::new());
+    pub static ref CONNECTIONS_PER_IP: Mutex<HashMap<IpAddr, u32>> = Mutex::new(HashMap::new());
+    pub static ref CONNECTIONS_PER_IP_LIMIT: u32 =
+        limit_from_env("MAX_CONNECTIONS_PER_IP", MAX_CONNECTIONS_PER_IP);
 }
 
 pub type ClientMessageQueue = Arc<ArrayQueue<ServerToClientMessage>>;
@@ -37,23 +86,136 @@ pub fn get_next_connection_id() -> u32 {
     NEXT_CONNECTION_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
 }
 
+fn limit_from_env(name: &str, default: u32) -> u32 {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("Ignoring bad {}={}, using {}", name, value, default);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+pub fn load_motd(path: &str) -> Option<String> {
+    match std::fs::read_to_string(path) {
+        Ok(motd) if !motd.trim().is_empty() => Some(motd.trim().to_string()),
+        Ok(_) => None,
+        Err(e) => {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                eprintln!("Couldn't read motd from {}: {:?}", path, e);
+            }
+            None
+        }
+    }
+}
+
 pub async fn init() {
     let listener = TcpListener::bind(SERVER_ADDR).await.unwrap();
+    if LAN_DISCOVERY {
+        let game_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let responder =
+                discovery::run_discovery_responder(SERVER_NAME.to_string(), game_port, MAX_PLAYERS);
+            // most likely another server on this host already has the port
+            if let Err(e) = responder.await {
+                eprintln!("Lan discovery stopped: {:?}", e);
+            }
+        });
+    }
     tokio::spawn(accept_connections(listener));
+    tokio::spawn(watch_for_stale_clients());
 }
 
 pub async fn accept_connections(listener: TcpListener) -> tokio::io::Result<()> {
     loop {
-        let (socket, _) = listener.accept().await?;
-        tokio::spawn(handle_connection(socket));
+        let (socket, addr) = listener.accept().await?;
+        let ip = addr.ip();
+        if !try_reserve_ip_slot(ip) {
+            eprintln!("Rejecting connection from {}: too many connections", ip);
+            drop(socket);
+            continue;
+        }
+        tokio::spawn(async move {
+            // handle_connection can bail out on any read/write error,
+            // so release the slot here rather than on each return path
+            let result = handle_connection(socket).await;
+            release_ip_slot(ip);
+            result
+        });
+    }
+}
+
+/// Counts a new connection against its ip, false if the ip is already at the limit.
+pub fn try_reserve_ip_slot(ip: IpAddr) -> bool {
+    let mut connections = CONNECTIONS_PER_IP.lock().unwrap();
+    let count = connections.entry(ip).or_insert(0);
+    if *count >= *CONNECTIONS_PER_IP_LIMIT {
+        return false;
+    }
+    *count += 1;
+    true
+}
+
+pub fn release_ip_slot(ip: IpAddr) {
+    let mut connections = CONNECTIONS_PER_IP.lock().unwrap();
+    if let Some(count) = connections.get_mut(&ip) {
+        *count -= 1;
+        if *count == 0 {
+            connections.remove(&ip);
+        }
+    }
+}
+
+pub fn message_cost(message: &ClientToServerMessage) -> f32 {
+    match message {
+        ClientToServerMessage::ChatMessage { .. }
+        | ClientToServerMessage::Relay { .. }
+        | ClientToServerMessage::Doodle { .. } => CHAT_MESSAGE_COST,
+        _ => DEFAULT_MESSAGE_COST,
+    }
+}
+
+pub struct TokenBucket {
+    capacity: f32,
+    refill_per_second: f32,
+    tokens: f32,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f32, refill_per_second: f32) -> Self {
+        Self {
+            capacity,
+            refill_per_second,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self, cost: f32) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f32();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+
+        if self.tokens < cost {
+            return false;
+        }
+        self.tokens -= cost;
+        true
     }
 }
 
 ////////////////////////    CLIENT RX/TX TASKS    ////////////////////////
 
 pub async fn handle_connection(mut socket: TcpStream) -> tokio::io::Result<()> {
+    socket.set_nodelay(TCP_NODELAY)?;
     let id = add_client().await;
-    socket.write_all(&id.to_be_bytes()).await?;
+    if let Err(e) = socket.write_all(&id.to_be_bytes()).await {
+        // already registered, don't leave a ghost behind
+        remove_client(id).await;
+        return Err(e);
+    }
 
     // announce that theres a new connection
     let to_self_message = ClientToServerMessageBundle {
@@ -67,37 +229,85 @@ pub async fn handle_connection(mut socket: TcpStream) -> tokio::io::Result<()> {
         );
     }
 
+    let disconnected = CLIENT_DISCONNECTED.read().await[&id].clone();
     let (mut socket_read_half, socket_write_half) = socket.into_split();
     tokio::spawn(continuously_transmit_any_outbound_messages(
         id,
         socket_write_half,
+        disconnected.clone(),
     ));
 
+    let mut rate_limiter = TokenBucket::new(RATE_LIMIT_BURST, RATE_LIMIT_REFILL_PER_SECOND);
+    let mut dropped_in_a_row = 0;
+    let mut window_start = Instant::now();
+    let mut bytes_this_window = 0;
+    let mut windows_over_cap_in_a_row = 0;
     let mut buffer = [0; 1024];
     loop {
-        let nbytes = socket_read_half.read(&mut buffer).await?;
+        // the tx task can give up on a client too, stop reading once it has
+        let read = tokio::select! {
+            read = socket_read_half.read(&mut buffer) => read,
+            _ = wait_until_disconnected(&disconnected) => return Ok(()),
+        };
+        let nbytes = match read {
+            Ok(nbytes) => nbytes,
+            Err(e) => {
+                signal_client_disconnected(id, &disconnected);
+                return Err(e);
+            }
+        };
         if nbytes == 0 {
-            // signal that the client has disconnected, via atomic bool
-            let disconnect_message = ClientToServerMessageBundle {
-                client_id: id,
-                message: ClientToServerMessage::Disconnect,
-            };
-            if INCOMING_MESSAGE_QUEUE.push(disconnect_message).is_err() {
+            signal_client_disconnected(id, &disconnected);
+            return Ok(());
+        }
+
+        // bandwidth cap
+        let window = tokio::time::Duration::from_secs(1);
+        if window_start.elapsed() >= window {
+            if bytes_this_window <= MAX_INBOUND_BYTES_PER_SECOND {
+                windows_over_cap_in_a_row = 0;
+            }
+            window_start = Instant::now();
+            bytes_this_window = 0;
+        }
+        bytes_this_window += nbytes;
+        if bytes_this_window > MAX_INBOUND_BYTES_PER_SECOND {
+            windows_over_cap_in_a_row += 1;
+            if windows_over_cap_in_a_row >= BANDWIDTH_KICK_AFTER_WINDOWS {
                 eprintln!(
-                    "Inbound message queue full: dropping disconnect message from {}",
+                    "Client {} kept exceeding the bandwidth cap: disconnecting",
                     id
                 );
+                signal_client_disconnected(id, &disconnected);
+                return Ok(());
             }
-            let client_disconnected_read = CLIENT_DISCONNECTED.read().await;
-            if let Some(disconnected) = client_disconnected_read.get(&id) {
-                disconnected.store(true, Ordering::SeqCst);
-            }
-            return Ok(());
+            // stop reading for the rest of the window, tcp backpressure slows them down.
+            // whatever they send meanwhile waits in the socket and counts toward the next window
+            let window_end = window_start + window;
+            tokio::time::sleep_until(window_end.into()).await;
+            window_start = Instant::now();
+            bytes_this_window = 0;
         }
 
         let result: Result<ClientToServerMessage, _> = bincode::deserialize(&buffer[..nbytes]);
         match result {
             Ok(result) => {
+                trace_message("rx", id, &result);
+                if !rate_limiter.try_take(message_cost(&result)) {
+                    dropped_in_a_row += 1;
+                    if dropped_in_a_row >= FLOOD_KICK_THRESHOLD {
+                        eprintln!("Client {} kept flooding: disconnecting", id);
+                        signal_client_disconnected(id, &disconnected);
+                        return Ok(());
+                    }
+                    continue;
+                }
+                dropped_in_a_row = 0;
+                // the read can land just as the tx task gives up, nothing goes out after the Disconnect
+                if disconnected.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                mark_client_heard(id);
                 let message_bundle = ClientToServerMessageBundle {
                     client_id: id,
                     message: result,
@@ -113,45 +323,191 @@ pub async fn handle_connection(mut socket: TcpStream) -> tokio::io::Result<()> {
     }
 }
 
+/// Flags the client's rx and tx tasks to wind down and queues a Disconnect for the game loop.
+/// Either task can call this, only the first call queues the Disconnect.
+pub fn signal_client_disconnected(id: u32, disconnected: &AtomicBool) {
+    if disconnected.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    queue_disconnect(id);
+}
+
+fn queue_disconnect(id: u32) {
+    let disconnect_message = ClientToServerMessageBundle {
+        client_id: id,
+        message: ClientToServerMessage::Disconnect,
+    };
+    if INCOMING_MESSAGE_QUEUE.push(disconnect_message).is_err() {
+        eprintln!(
+            "Inbound message queue full: dropping disconnect message from {}",
+            id
+        );
+    }
+}
+
+async fn wait_until_disconnected(disconnected: &AtomicBool) {
+    while !disconnected.load(Ordering::SeqCst) {
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+}
+
 pub async fn continuously_transmit_any_outbound_messages(
     id: u32,
     mut socket_write_half: tokio::net::tcp::OwnedWriteHalf,
+    disconnected: Arc<AtomicBool>,
 ) -> io::Result<()> {
     loop {
         // check for disconnect
-        {
-            let client_status_read = CLIENT_DISCONNECTED.read().await;
-            if let Some(disconnected_flag) = client_status_read.get(&id) {
-                if disconnected_flag.load(Ordering::Relaxed) {
-                    drop(client_status_read);
-                    remove_client(id).await; //  remove client allocated bookkeeping resources
-                    return Ok(());
-                }
-            }
-            drop(client_status_read);
+        if disconnected.load(Ordering::SeqCst) {
+            remove_client(id).await; //  remove client allocated bookkeeping resources
+            return Ok(());
         }
 
-        // transmit any outbound messages
-        let clients_read = CLIENT_OUTBOUND_MAILBOXES.read().await;
-        if let Some(outgoing_messages) = clients_read.get(&id) {
-            if let Some(message) = outgoing_messages.pop() {
-                match bincode::serialize(&message) {
-                    Ok(binary_message) => {
-                        socket_write_half.write_all(&binary_message).await?;
-                    }
-                    Err(e) => {
-                        eprintln!("Error serializing message: {:?}", e);
+        // transmit any outbound messages.
+        // the mailbox lock is only held for the pop, a slow write mustn't block add/remove_client
+        let next_message = CLIENT_OUTBOUND_MAILBOXES
+            .read()
+            .await
+            .get(&id)
+            .and_then(|outgoing_messages| outgoing_messages.pop());
+        if let Some(message) = next_message {
+            trace_message("tx", id, &message);
+            match bincode::serialize(&message) {
+                Ok(binary_message) => {
+                    // a client that stops reading stalls the write, and its mailbox
+                    // backs up behind it, so past the timeout give up on them
+                    let write = socket_write_half.write_all(&binary_message);
+                    match tokio::time::timeout(SLOW_CLIENT_TIMEOUT, write).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => {
+                            signal_client_disconnected(id, &disconnected);
+                            remove_client(id).await;
+                            return Err(e);
+                        }
+                        Err(_) => {
+                            eprintln!("Client {} can't keep up: disconnecting", id);
+                            signal_client_disconnected(id, &disconnected);
+                            remove_client(id).await;
+                            return Ok(());
+                        }
                     }
                 }
+                Err(e) => {
+                    eprintln!("Error serializing message: {:?}", e);
+                }
             }
         }
-        drop(clients_read);
 
         // Some delay, or await on an event to prevent busy-waiting
         tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
     }
 }
 
+////////////////////////    IN PROCESS CLIENTS    ////////////////////////
+
+/// Registers a client that talks to the game loop through queues instead of a socket,
+/// so server logic can be driven without binding ports or waiting on the network.
+/// Setting the transport's disconnected flag disconnects the client like a closed socket.
+pub async fn connect_in_process() -> (u32, ClientTransport) {
+    let id = add_client().await;
+    let incoming = CLIENT_OUTBOUND_MAILBOXES.read().await[&id].clone();
+    let disconnected = CLIENT_DISCONNECTED.read().await[&id].clone();
+    let outbound = Arc::new(ArrayQueue::new(1000));
+
+    let to_self_message = ClientToServerMessageBundle {
+        client_id: id,
+        message: ClientToServerMessage::Connect,
+    };
+    if INCOMING_MESSAGE_QUEUE.push(to_self_message).is_err() {
+        eprintln!(
+            "Inbound message queue full: dropping connect message from {}",
+            id
+        );
+    }
+
+    tokio::spawn(pump_in_process_client(
+        id,
+        outbound.clone(),
+        disconnected.clone(),
+    ));
+    let transport = QueueTransport {
+        outbound,
+        incoming,
+        disconnected,
+    };
+    (id, transport)
+}
+
+/// Stands in for handle_connection: tags the client's messages with its id for the game loop.
+async fn pump_in_process_client(
+    id: u32,
+    outbound: Arc<ArrayQueue<ClientToServerMessage>>,
+    disconnected: Arc<AtomicBool>,
+) {
+    loop {
+        while let Some(message) = outbound.pop() {
+            trace_message("rx", id, &message);
+            mark_client_heard(id);
+            let message_bundle = ClientToServerMessageBundle {
+                client_id: id,
+                message,
+            };
+            if INCOMING_MESSAGE_QUEUE.push(message_bundle).is_err() {
+                eprintln!("Inbound message queue full: dropping message from {}", id);
+            }
+        }
+        // the client end sets the flag itself, so there's nobody else to queue the Disconnect
+        if disconnected.load(Ordering::SeqCst) {
+            queue_disconnect(id);
+            remove_client(id).await;
+            return;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+}
+
+////////////////////////    STALENESS    ////////////////////////
+
+pub fn mark_client_heard(id: u32) {
+    if let Some(last_heard) = CLIENT_LAST_HEARD.lock().unwrap().get_mut(&id) {
+        last_heard.0 = Instant::now();
+    }
+}
+
+/// How long since each client last sent anything, for metrics and debugging.
+pub fn client_staleness() -> HashMap<u32, tokio::time::Duration> {
+    CLIENT_LAST_HEARD
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(&id, (last_heard, _))| (id, last_heard.elapsed()))
+        .collect()
+}
+
+/// Tells everyone when a client goes quiet for too long, and again when they're back.
+pub async fn watch_for_stale_clients() {
+    loop {
+        tokio::time::sleep(STALE_CLIENT_AFTER / 4).await;
+
+        let mut changed = Vec::new();
+        {
+            let mut last_heard = CLIENT_LAST_HEARD.lock().unwrap();
+            for (&id, (heard_at, lagging)) in last_heard.iter_mut() {
+                let stale = heard_at.elapsed() > STALE_CLIENT_AFTER;
+                if stale != *lagging {
+                    *lagging = stale;
+                    changed.push((id, stale));
+                }
+            }
+        }
+
+        for (id, lagging) in changed {
+            let outbound_message = ServerToClientMessage::PlayerLagging { id, lagging };
+            broadcast_to_all_except(id, outbound_message).await;
+        }
+    }
+}
+
 ////////////////////////    CLIENT BOOKKEEPING    ////////////////////////
 pub async fn add_client() -> u32 {
     let id = get_next_connection_id();
@@ -166,6 +522,11 @@ pub async fn add_client() -> u32 {
     let mut client_status_write = CLIENT_DISCONNECTED.write().await;
     client_status_write.insert(id, disconnected.clone());
 
+    CLIENT_LAST_HEARD
+        .lock()
+        .unwrap()
+        .insert(id, (Instant::now(), false));
+
     println!("New Connected Client: Assigned ID: {}", id);
     id
 }
@@ -180,6 +541,8 @@ pub async fn remove_client(id: u32) {
     let mut client_status_write = CLIENT_DISCONNECTED.write().await;
     client_status_write.remove(&id);
 
+    CLIENT_LAST_HEARD.lock().unwrap().remove(&id);
+
     println!("Client {} network resources cleaned up.", id);
 }
 
@@ -215,3 +578,319 @@ pub async fn broadcast_to_all(message: ServerToClientMessage) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::{process_message_queue, send_welcome};
+    use crate::transport::Transport;
+    use tokio::task::JoinHandle;
+    use tokio::time::Duration;
+
+    lazy_static! {
+        // the queues and client maps are global, so tests touching them take turns
+        static ref SERVER_TEST_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+    }
+
+    /// Runs handle_connection on one end of a loopback socket, returns the client end.
+    async fn connect_over_loopback() -> (u32, TcpStream, JoinHandle<io::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let server = tokio::spawn(handle_connection(socket));
+
+        let mut id_buffer = [0u8; 4];
+        client.read_exact(&mut id_buffer).await.unwrap();
+        (u32::from_be_bytes(id_buffer), client, server)
+    }
+
+    /// Drains the inbound queue, keeping only what came from one client.
+    fn take_messages_from(id: u32) -> Vec<ClientToServerMessage> {
+        std::iter::from_fn(|| INCOMING_MESSAGE_QUEUE.pop())
+            .filter(|bundle| bundle.client_id == id)
+            .map(|bundle| bundle.message)
+            .collect()
+    }
+
+    /// Runs the game loop for a while so the pump tasks and process_message_queue both get turns.
+    async fn run_server_for(duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            process_message_queue().await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn received(transport: &ClientTransport) -> Vec<ServerToClientMessage> {
+        std::iter::from_fn(|| transport.try_recv()).collect()
+    }
+
+    fn chat(message: &str) -> ClientToServerMessage {
+        ClientToServerMessage::ChatMessage {
+            message: message.to_string(),
+        }
+    }
+
+    /// Leftovers from other tests would otherwise show up as this test's messages.
+    fn clear_inbound_queue() {
+        while INCOMING_MESSAGE_QUEUE.pop().is_some() {}
+    }
+
+    async fn disconnect_all(clients: &[&ClientTransport]) {
+        for client in clients {
+            client.disconnected.store(true, Ordering::SeqCst);
+        }
+        run_server_for(Duration::from_millis(50)).await;
+    }
+
+    fn count_disconnects(messages: &[ClientToServerMessage]) -> usize {
+        messages
+            .iter()
+            .filter(|message| matches!(message, ClientToServerMessage::Disconnect))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn client_that_stops_reading_is_disconnected_once() {
+        let _lock = SERVER_TEST_LOCK.lock().await;
+        // never read from it, the socket buffers fill and the tx task's writes stall
+        let (id, _client, server) = connect_over_loopback().await;
+        let mailbox = CLIENT_OUTBOUND_MAILBOXES.read().await[&id].clone();
+        while mailbox.len() < 50 {
+            let message = ServerToClientMessage::ChatMessage {
+                from: id,
+                message: "x".repeat(1 << 20),
+            };
+            mailbox.push(message).unwrap();
+        }
+
+        // the read loop has to notice on its own, the client never closes the socket
+        let result = tokio::time::timeout(SLOW_CLIENT_TIMEOUT * 3, server).await;
+        assert!(matches!(result, Ok(Ok(Ok(())))));
+        // the tx task cleans up right after flagging the disconnect
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        assert!(!CLIENT_OUTBOUND_MAILBOXES.read().await.contains_key(&id));
+        assert_eq!(count_disconnects(&take_messages_from(id)), 1);
+    }
+
+    #[test]
+    fn token_bucket_throttles_a_burst_then_refills() {
+        let mut bucket = TokenBucket::new(10.0, 100.0);
+        for _ in 0..10 {
+            assert!(bucket.try_take(DEFAULT_MESSAGE_COST));
+        }
+        assert!(!bucket.try_take(DEFAULT_MESSAGE_COST));
+
+        // 100 per second, so 50ms buys back about five messages
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(bucket.try_take(DEFAULT_MESSAGE_COST));
+        assert!(!bucket.try_take(CHAT_MESSAGE_COST * 2.0));
+    }
+
+    #[test]
+    fn ip_slots_stop_at_the_cap_and_free_up_on_release() {
+        // a documentation-only address, so nothing else in the tests shares its counter
+        let ip: IpAddr = "192.0.2.57".parse().unwrap();
+        for _ in 0..*CONNECTIONS_PER_IP_LIMIT {
+            assert!(try_reserve_ip_slot(ip));
+        }
+        assert!(!try_reserve_ip_slot(ip));
+
+        release_ip_slot(ip);
+        assert!(try_reserve_ip_slot(ip));
+        assert!(!try_reserve_ip_slot(ip));
+
+        for _ in 0..*CONNECTIONS_PER_IP_LIMIT {
+            release_ip_slot(ip);
+        }
+        assert!(!CONNECTIONS_PER_IP.lock().unwrap().contains_key(&ip));
+    }
+
+    #[tokio::test]
+    async fn in_process_join_is_announced_to_others() {
+        let _lock = SERVER_TEST_LOCK.lock().await;
+        clear_inbound_queue();
+        let (_, a) = connect_in_process().await;
+        run_server_for(Duration::from_millis(50)).await;
+        assert!(matches!(
+            received(&a).first(),
+            Some(ServerToClientMessage::Welcome { .. })
+        ));
+
+        let (b_id, b) = connect_in_process().await;
+        b.send(chat("hi"));
+        run_server_for(Duration::from_millis(100)).await;
+
+        let seen_by_a = received(&a);
+        let joined_at = seen_by_a.iter().position(
+            |message| matches!(message, ServerToClientMessage::PlayerJoined { id } if *id == b_id),
+        );
+        let chat_at = seen_by_a.iter().position(|message| {
+            matches!(message, ServerToClientMessage::ChatMessage { from, .. } if *from == b_id)
+        });
+        assert!(joined_at.is_some());
+        assert!(joined_at < chat_at);
+        disconnect_all(&[&a, &b]).await;
+    }
+
+    #[tokio::test]
+    async fn in_process_chat_reaches_everyone_but_the_sender() {
+        let _lock = SERVER_TEST_LOCK.lock().await;
+        clear_inbound_queue();
+        let (a_id, a) = connect_in_process().await;
+        let (_, b) = connect_in_process().await;
+        let (_, c) = connect_in_process().await;
+        run_server_for(Duration::from_millis(50)).await;
+        for client in [&a, &b, &c] {
+            received(client);
+        }
+
+        a.send(chat("hello"));
+        run_server_for(Duration::from_millis(100)).await;
+
+        let is_hello = |message: &ServerToClientMessage| {
+            matches!(message, ServerToClientMessage::ChatMessage { from, message }
+                if *from == a_id && message == "hello")
+        };
+        assert!(received(&b).iter().any(is_hello));
+        assert!(received(&c).iter().any(is_hello));
+        assert!(!received(&a).iter().any(is_hello));
+        disconnect_all(&[&a, &b, &c]).await;
+    }
+
+    #[tokio::test]
+    async fn in_process_disconnect_cleans_up_and_tells_the_others() {
+        let _lock = SERVER_TEST_LOCK.lock().await;
+        clear_inbound_queue();
+        let (_, a) = connect_in_process().await;
+        let (b_id, b) = connect_in_process().await;
+        // b has to be announced before anyone sees them leave
+        b.send(chat("bye"));
+        run_server_for(Duration::from_millis(100)).await;
+        received(&a);
+
+        b.disconnected.store(true, Ordering::SeqCst);
+        run_server_for(Duration::from_millis(100)).await;
+
+        let left: Vec<u32> = received(&a)
+            .into_iter()
+            .filter_map(|message| match message {
+                ServerToClientMessage::PlayerLeft { id } => Some(id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(left.iter().filter(|&&id| id == b_id).count(), 1);
+        assert!(!CLIENT_OUTBOUND_MAILBOXES.read().await.contains_key(&b_id));
+        assert!(!CLIENT_DISCONNECTED.read().await.contains_key(&b_id));
+        assert!(!CLIENT_LAST_HEARD.lock().unwrap().contains_key(&b_id));
+        disconnect_all(&[&a]).await;
+    }
+
+    #[test]
+    fn motd_file_is_trimmed_and_blank_means_none() {
+        let path = std::env::temp_dir().join(format!("motd_test_{}.txt", std::process::id()));
+        let path_str = path.to_str().unwrap();
+
+        std::fs::write(&path, "  be nice\n\n").unwrap();
+        assert_eq!(load_motd(path_str).as_deref(), Some("be nice"));
+        std::fs::write(&path, " \n").unwrap();
+        assert_eq!(load_motd(path_str), None);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(load_motd(path_str), None);
+    }
+
+    #[tokio::test]
+    async fn connecting_client_gets_the_motd_after_welcome() {
+        let _lock = SERVER_TEST_LOCK.lock().await;
+        let (id, client) = connect_in_process().await;
+        // stands in for the Connect handler, with a motd instead of whatever motd.txt says
+        send_welcome(id, Some("be nice")).await;
+
+        let messages = received(&client);
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                ServerToClientMessage::Welcome { .. },
+                ServerToClientMessage::SystemMessage { message },
+            ] if message == "be nice"
+        ));
+        disconnect_all(&[&client]).await;
+    }
+
+    #[tokio::test]
+    async fn client_over_the_bandwidth_cap_is_disconnected() {
+        let _lock = SERVER_TEST_LOCK.lock().await;
+        clear_inbound_queue();
+        let (id, client, server) = connect_over_loopback().await;
+        let (_client_read, mut client_write) = client.into_split();
+        // unparseable bytes, so only the byte cap can be what kicks them
+        let flood = tokio::spawn(async move {
+            let chunk = [0xff; 4096];
+            while client_write.write_all(&chunk).await.is_ok() {}
+        });
+
+        let kick_after = Duration::from_secs(BANDWIDTH_KICK_AFTER_WINDOWS as u64 + 5);
+        let result = tokio::time::timeout(kick_after, server).await;
+        assert!(matches!(result, Ok(Ok(Ok(())))));
+        assert_eq!(count_disconnects(&take_messages_from(id)), 1);
+        flood.abort();
+    }
+
+    #[tokio::test]
+    async fn doodles_outside_the_box_are_not_broadcast() {
+        let _lock = SERVER_TEST_LOCK.lock().await;
+        clear_inbound_queue();
+        let (_, a) = connect_in_process().await;
+        let (b_id, b) = connect_in_process().await;
+        run_server_for(Duration::from_millis(50)).await;
+        received(&a);
+
+        let huge = MAX_DOODLE_EXTENT * 100.0;
+        b.send(ClientToServerMessage::Doodle {
+            strokes: vec![(0.0, 0.0), (huge, 0.0)],
+        });
+        b.send(ClientToServerMessage::Doodle {
+            strokes: vec![(0.0, 0.0), (MAX_DOODLE_EXTENT, -MAX_DOODLE_EXTENT)],
+        });
+        run_server_for(Duration::from_millis(100)).await;
+
+        let doodles: Vec<Vec<(f32, f32)>> = received(&a)
+            .into_iter()
+            .filter_map(|message| match message {
+                ServerToClientMessage::Doodle { id, strokes } if id == b_id => Some(strokes),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            doodles,
+            vec![vec![(0.0, 0.0), (MAX_DOODLE_EXTENT, -MAX_DOODLE_EXTENT)]]
+        );
+        disconnect_all(&[&a, &b]).await;
+    }
+
+    #[tokio::test]
+    async fn client_that_drops_before_the_grace_period_is_never_seen() {
+        let _lock = SERVER_TEST_LOCK.lock().await;
+        clear_inbound_queue();
+        let (_, a) = connect_in_process().await;
+        let (b_id, b) = connect_in_process().await;
+        let (c_id, c) = connect_in_process().await;
+        b.disconnected.store(true, Ordering::SeqCst);
+        run_server_for(JOIN_ANNOUNCE_GRACE + Duration::from_millis(200)).await;
+
+        let seen_by_a = received(&a);
+        let mentions = |who: u32| {
+            seen_by_a.iter().any(|message| match message {
+                ServerToClientMessage::PlayerJoined { id }
+                | ServerToClientMessage::PlayerLeft { id } => *id == who,
+                _ => false,
+            })
+        };
+        assert!(!mentions(b_id));
+        // c stayed connected but quiet, so the grace period ran out and they were announced
+        assert!(mentions(c_id));
+        disconnect_all(&[&a, &c]).await;
+    }
+}
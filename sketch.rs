@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 
 use glam::Vec2;
 use raylib::prelude::*;
@@ -6,6 +7,11 @@ use raylib::prelude::*;
 use crate::protocol::Player;
 
 pub const FRAMES_PER_SECOND: u32 = 60;
+/// None runs the loop uncapped.
+pub const TARGET_FPS: Option<u32> = Some(FRAMES_PER_SECOND);
+const FRAME_TIME_SMOOTHING_SAMPLES: usize = 4;
+// anything longer than this is an alt-tab or a hitch, not real movement time
+const MAX_FRAME_TIME: f32 = 0.1;
 
 impl ClientState {
     pub fn new() -> Self {
@@ -16,36 +22,584 @@ impl ClientState {
             player_id: None,
 
             players: HashMap::new(),
+
+            mouse_follow: MouseFollow::new(),
+            camera: FollowCamera::new(),
+            target_fps: TARGET_FPS,
+            frame_timer: FrameTimer::new(),
+            animations: PlayerAnimations::new(),
+            console: Console::new(),
+            quality: AdaptiveQuality::new(),
         }
     }
 }
 
 const PLAYER_SPEED: f32 = 1.0;
 
+/// Tuning for steering the player toward the cursor.
+#[derive(Debug, Clone, Copy)]
+pub struct MouseFollow {
+    /// Within this many pixels of the cursor the player just stops, so it doesn't jitter on top of it.
+    pub dead_zone: f32,
+    /// Speed ramps up from zero at the dead zone to full at this far past it.
+    pub ramp_distance: f32,
+    /// Multiplier on PLAYER_SPEED.
+    pub sensitivity: f32,
+}
+
+impl MouseFollow {
+    pub fn new() -> Self {
+        Self {
+            dead_zone: 4.0,
+            ramp_distance: 100.0,
+            sensitivity: 1.0,
+        }
+    }
+
+    pub fn velocity_toward(&self, pos: Vec2, target: Vec2) -> Vec2 {
+        let offset = target - pos;
+        let distance = offset.length();
+        if distance <= self.dead_zone {
+            return Vec2::ZERO;
+        }
+        let ramp = ((distance - self.dead_zone) / self.ramp_distance.max(1.0)).min(1.0);
+        offset / distance * PLAYER_SPEED * self.sensitivity * ramp
+    }
+}
+
+impl Default for MouseFollow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+pub const PLAYER_RADIUS: f32 = 8.0;
+/// None draws players as plain circles.
+pub const PLAYER_SPRITE_PATH: Option<&str> = Some("assets/player.png");
+
+/// Loads the player sprite, or None if there isn't one so we fall back to circles.
+pub fn load_player_sprite(rl: &mut RaylibHandle, rlt: &RaylibThread) -> Option<Texture2D> {
+    let path = PLAYER_SPRITE_PATH?;
+    match rl.load_texture(rlt, path) {
+        Ok(texture) => Some(texture),
+        Err(e) => {
+            eprintln!(
+                "Failed to load player sprite {}: {}, drawing circles",
+                path, e
+            );
+            None
+        }
+    }
+}
+
+pub fn draw_player(
+    d: &mut RaylibDrawHandle,
+    sprite: Option<&Texture2D>,
+    player: &Player,
+    radius: f32,
+) {
+    let center = Vector2::new(player.pos.x, player.pos.y);
+    match sprite {
+        Some(texture) => {
+            // face the way they're moving, sprites are drawn facing +x
+            let rotation = if player.vel.length_squared() > 0.0 {
+                player.vel.y.atan2(player.vel.x).to_degrees()
+            } else {
+                0.0
+            };
+            let source = Rectangle::new(0.0, 0.0, texture.width as f32, texture.height as f32);
+            let size = radius * 2.0;
+            let dest = Rectangle::new(center.x, center.y, size, size);
+            let origin = Vector2::new(radius, radius);
+            d.draw_texture_pro(texture, source, dest, origin, rotation, Color::WHITE);
+        }
+        None => {
+            d.draw_circle_v(center, radius, Color::WHITE);
+        }
+    }
+}
+
+// past these the per-player draw calls start costing whole frames
+const MAX_DRAWN_PLAYERS: usize = 1000;
+const MAX_LABELED_PLAYERS: usize = 32;
+const LABEL_DISTANCE: f32 = 300.0;
+// blobs this small on screen don't need anything fancier than a square
+const MIN_DETAILED_RADIUS: f32 = 3.0;
+// half a 60fps frame, whoever is left past this isn't drawn this frame
+const PLAYER_DRAW_BUDGET: Duration = Duration::from_millis(8);
+
+/// Who gets drawn this frame, nearest first. The first `labeled` of them get name labels.
+pub struct PlayerDrawPlan<'a> {
+    pub visible: Vec<&'a Player>,
+    pub labeled: usize,
+}
+
+/// Culls everyone outside the screen, nearest first, capped at MAX_DRAWN_PLAYERS.
+pub fn plan_player_draws<'a>(
+    players: &'a HashMap<u32, Player>,
+    animations: &'a PlayerAnimations,
+    view_center: Vec2,
+    screen_size: Vec2,
+) -> PlayerDrawPlan<'a> {
+    let half_screen = screen_size / 2.0;
+    let view_min = view_center - half_screen - Vec2::splat(PLAYER_RADIUS);
+    let view_max = view_center + half_screen + Vec2::splat(PLAYER_RADIUS);
+
+    // players that already left stay drawn, shrinking, until their animation finishes
+    let leaving = animations
+        .leaving
+        .values()
+        .map(|(_, player)| player)
+        .filter(|player| !players.contains_key(&player.id));
+    let mut visible: Vec<(f32, &Player)> = players
+        .values()
+        .chain(leaving)
+        .filter(|p| p.pos.cmpge(view_min).all() && p.pos.cmple(view_max).all())
+        .map(|p| (p.pos.distance_squared(view_center), p))
+        .collect();
+    // nearest first, so if we have to cut anything it's the far away ones
+    visible.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+    visible.truncate(MAX_DRAWN_PLAYERS);
+
+    let labeled = visible
+        .iter()
+        .take(MAX_LABELED_PLAYERS)
+        .take_while(|(distance_squared, _)| *distance_squared < LABEL_DISTANCE * LABEL_DISTANCE)
+        .count();
+    PlayerDrawPlan {
+        visible: visible.into_iter().map(|(_, player)| player).collect(),
+        labeled,
+    }
+}
+
+/// How much of the plan draw_players got through and how long it took.
+#[derive(Debug, Clone, Copy)]
+pub struct PlayerDrawStats {
+    pub visible: usize,
+    pub drawn: usize,
+    pub draw_time: Duration,
+}
+
+/// Draws everyone who is on screen, with labels only for the closest few.
+/// Stops early once PLAYER_DRAW_BUDGET is spent.
+pub fn draw_players(
+    d: &mut RaylibDrawHandle,
+    sprite: Option<&Texture2D>,
+    players: &HashMap<u32, Player>,
+    animations: &PlayerAnimations,
+    view_center: Vec2,
+    quality: QualityLevel,
+) -> PlayerDrawStats {
+    let started = Instant::now();
+    let screen_size = Vec2::new(d.get_screen_width() as f32, d.get_screen_height() as f32);
+    let plan = plan_player_draws(players, animations, view_center, screen_size);
+
+    let mut drawn = 0;
+    for (i, player) in plan.visible.iter().enumerate() {
+        if started.elapsed() > PLAYER_DRAW_BUDGET {
+            break;
+        }
+        drawn += 1;
+
+        let radius = animations.radius(player.id);
+        if radius < MIN_DETAILED_RADIUS || quality == QualityLevel::Minimal {
+            let size = (radius * 2.0).max(1.0) as i32;
+            d.draw_rectangle(
+                (player.pos.x - radius) as i32,
+                (player.pos.y - radius) as i32,
+                size,
+                size,
+                Color::WHITE,
+            );
+            continue;
+        }
+
+        let sprite = if quality >= QualityLevel::NoLabels {
+            sprite
+        } else {
+            None
+        };
+        draw_player(d, sprite, player, radius);
+        if quality == QualityLevel::Full && i < plan.labeled {
+            let label = player.id.to_string();
+            let x = (player.pos.x - radius) as i32;
+            let y = (player.pos.y - radius) as i32 - 12;
+            d.draw_text(&label, x, y, 10, Color::LIGHTGRAY);
+        }
+    }
+    PlayerDrawStats {
+        visible: plan.visible.len(),
+        drawn,
+        draw_time: started.elapsed(),
+    }
+}
+
+/// Rendering detail, lowest first. Features are shed from the top down:
+/// labels go first, then sprites, then everything is a plain square.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QualityLevel {
+    Minimal,
+    NoSprites,
+    NoLabels,
+    Full,
+}
+
+impl QualityLevel {
+    fn lower(self) -> Self {
+        match self {
+            QualityLevel::Full => QualityLevel::NoLabels,
+            QualityLevel::NoLabels => QualityLevel::NoSprites,
+            _ => QualityLevel::Minimal,
+        }
+    }
+
+    fn higher(self) -> Self {
+        match self {
+            QualityLevel::Minimal => QualityLevel::NoSprites,
+            QualityLevel::NoSprites => QualityLevel::NoLabels,
+            _ => QualityLevel::Full,
+        }
+    }
+}
+
+// the gap between these, plus waiting a while before going back up, keeps it from flapping
+// fractions of the target fps, so a client capped at 30 isn't measured against 60
+const QUALITY_DOWNGRADE_BELOW_TARGET: f32 = 0.75;
+const QUALITY_UPGRADE_ABOVE_TARGET: f32 = 0.97;
+// uncapped there's no target to compare against
+const UNCAPPED_DOWNGRADE_BELOW_FPS: u32 = 45;
+const UNCAPPED_UPGRADE_ABOVE_FPS: u32 = 58;
+const QUALITY_DOWNGRADE_AFTER_SECONDS: f32 = 1.0;
+const QUALITY_UPGRADE_AFTER_SECONDS: f32 = 5.0;
+
+pub struct AdaptiveQuality {
+    pub level: QualityLevel,
+    /// Pins the level for people who'd rather have fixed quality.
+    pub override_level: Option<QualityLevel>,
+    slow_since: Option<Instant>,
+    fast_since: Option<Instant>,
+}
+
+impl AdaptiveQuality {
+    pub fn new() -> Self {
+        Self {
+            level: QualityLevel::Full,
+            override_level: None,
+            slow_since: None,
+            fast_since: None,
+        }
+    }
+
+    pub fn current(&self) -> QualityLevel {
+        self.override_level.unwrap_or(self.level)
+    }
+
+    /// Feed it the measured fps once per frame, along with the fps we're capped at.
+    pub fn step(&mut self, fps: u32, target_fps: Option<u32>) {
+        self.step_at(fps, target_fps, Instant::now());
+    }
+
+    fn step_at(&mut self, fps: u32, target_fps: Option<u32>, now: Instant) {
+        let (downgrade_below, upgrade_above) = match target_fps {
+            Some(target) if target > 0 => (
+                target as f32 * QUALITY_DOWNGRADE_BELOW_TARGET,
+                target as f32 * QUALITY_UPGRADE_ABOVE_TARGET,
+            ),
+            _ => (
+                UNCAPPED_DOWNGRADE_BELOW_FPS as f32,
+                UNCAPPED_UPGRADE_ABOVE_FPS as f32,
+            ),
+        };
+        let fps = fps as f32;
+        if fps < downgrade_below {
+            self.fast_since = None;
+            let slow_since = *self.slow_since.get_or_insert(now);
+            if (now - slow_since).as_secs_f32() >= QUALITY_DOWNGRADE_AFTER_SECONDS {
+                self.level = self.level.lower();
+                self.slow_since = None;
+            }
+        } else if fps > upgrade_above {
+            self.slow_since = None;
+            let fast_since = *self.fast_since.get_or_insert(now);
+            if (now - fast_since).as_secs_f32() >= QUALITY_UPGRADE_AFTER_SECONDS {
+                self.level = self.level.higher();
+                self.fast_since = None;
+            }
+        } else {
+            self.slow_since = None;
+            self.fast_since = None;
+        }
+    }
+}
+
+impl Default for AdaptiveQuality {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const JOIN_ANIMATION_SECONDS: f32 = 0.3;
+
+/// Grows remote players in when they show up and shrinks them out when they leave,
+/// instead of popping. Leaving players are kept here until they've shrunk away.
+pub struct PlayerAnimations {
+    pub joined_at: HashMap<u32, Instant>,
+    pub leaving: HashMap<u32, (Instant, Player)>,
+    /// Everyone as of last frame, to spot who came and went.
+    known: HashMap<u32, Player>,
+}
+
+impl PlayerAnimations {
+    pub fn new() -> Self {
+        Self {
+            joined_at: HashMap::new(),
+            leaving: HashMap::new(),
+            known: HashMap::new(),
+        }
+    }
+
+    pub fn on_join(&mut self, id: u32) {
+        self.joined_at.insert(id, Instant::now());
+        self.leaving.remove(&id);
+    }
+
+    pub fn on_leave(&mut self, player: Player) {
+        self.joined_at.remove(&player.id);
+        self.leaving.insert(player.id, (Instant::now(), player));
+    }
+
+    pub fn radius(&self, id: u32) -> f32 {
+        if let Some(joined_at) = self.joined_at.get(&id) {
+            let t = joined_at.elapsed().as_secs_f32() / JOIN_ANIMATION_SECONDS;
+            return PLAYER_RADIUS * t.min(1.0);
+        }
+        if let Some((left_at, _)) = self.leaving.get(&id) {
+            let t = left_at.elapsed().as_secs_f32() / JOIN_ANIMATION_SECONDS;
+            return PLAYER_RADIUS * (1.0 - t).max(0.0);
+        }
+        PLAYER_RADIUS
+    }
+
+    /// Call once per frame. Diffs against last frame's players, so it doesn't
+    /// matter what adds or removes them, then drops finished animations.
+    pub fn step(&mut self, players: &HashMap<u32, Player>) {
+        for &id in players.keys() {
+            if !self.known.contains_key(&id) {
+                self.on_join(id);
+            }
+        }
+        let left: Vec<u32> = self
+            .known
+            .keys()
+            .filter(|id| !players.contains_key(id))
+            .copied()
+            .collect();
+        for id in left {
+            if let Some(player) = self.known.remove(&id) {
+                self.on_leave(player);
+            }
+        }
+        self.known.clone_from(players);
+
+        let duration = std::time::Duration::from_secs_f32(JOIN_ANIMATION_SECONDS);
+        self.joined_at
+            .retain(|_, joined_at| joined_at.elapsed() < duration);
+        self.leaving
+            .retain(|_, (left_at, _)| left_at.elapsed() < duration);
+    }
+}
+
+impl Default for PlayerAnimations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Averages the last few frame times so a single spike doesn't jerk movement.
+/// Only use the smoothed dt for movement, network timing wants the real one.
+pub struct FrameTimer {
+    samples: VecDeque<f32>,
+    applied_target_fps: Option<Option<u32>>,
+}
+
+impl FrameTimer {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(FRAME_TIME_SMOOTHING_SAMPLES),
+            applied_target_fps: None,
+        }
+    }
+
+    /// Hands the cap to raylib whenever it changes, so it can be switched while running.
+    pub fn apply_target_fps(&mut self, rl: &mut RaylibHandle, target_fps: Option<u32>) {
+        if self.applied_target_fps == Some(target_fps) {
+            return;
+        }
+        // raylib treats a target of 0 as uncapped
+        rl.set_target_fps(target_fps.unwrap_or(0));
+        self.applied_target_fps = Some(target_fps);
+    }
+
+    pub fn smooth(&mut self, dt: f32) -> f32 {
+        let dt = dt.clamp(0.0, MAX_FRAME_TIME);
+        if self.samples.len() == FRAME_TIME_SMOOTHING_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(dt);
+        self.samples.iter().sum::<f32>() / self.samples.len() as f32
+    }
+}
+
+impl Default for FrameTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Eases toward the player instead of sticking to them, so corrections don't shake the view.
+pub struct FollowCamera {
+    pub pos: Vec2,
+    /// Higher catches up faster, roughly 1/seconds.
+    pub stiffness: f32,
+    /// How many frames of velocity to lead the player by.
+    pub look_ahead_frames: f32,
+    /// Debug: glue the camera to the player to compare against the eased one.
+    pub rigid: bool,
+}
+
+impl FollowCamera {
+    pub fn new() -> Self {
+        Self {
+            pos: Vec2::ZERO,
+            stiffness: 8.0,
+            look_ahead_frames: 20.0,
+            rigid: false,
+        }
+    }
+
+    pub fn step(&mut self, target_pos: Vec2, target_vel: Vec2, dt: f32) {
+        if self.rigid {
+            self.pos = target_pos;
+            return;
+        }
+        let goal = target_pos + target_vel * self.look_ahead_frames;
+        // exponential ease, same feel at any frame rate
+        let t = 1.0 - (-self.stiffness * dt).exp();
+        self.pos += (goal - self.pos) * t;
+    }
+
+    pub fn to_camera2d(&self, screen_width: i32, screen_height: i32) -> Camera2D {
+        Camera2D {
+            target: Vector2::new(self.pos.x, self.pos.y),
+            offset: Vector2::new(screen_width as f32 / 2.0, screen_height as f32 / 2.0),
+            rotation: 0.0,
+            zoom: 1.0,
+        }
+    }
+}
+
+impl Default for FollowCamera {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub fn step(rl: &mut RaylibHandle, rlt: &mut RaylibThread, state: &mut ClientState) {
+    state.frame_timer.apply_target_fps(rl, state.target_fps);
+    let dt = state.frame_timer.smooth(rl.get_frame_time());
+    state.animations.step(&state.players);
+    state.quality.step(rl.get_fps(), state.target_fps);
+    if let Some(command) = state.console.step(rl) {
+        apply_console_command(state, command);
+    }
+
     // set the mouse
+    let Some(player_id) = state.player_id else {
+        return;
+    };
+    if rl.is_key_pressed(KeyboardKey::KEY_F2) {
+        state.camera.rigid = !state.camera.rigid;
+    }
+
+    // the cursor is in screen space, the player is in world space
+    let camera = state
+        .camera
+        .to_camera2d(rl.get_screen_width(), rl.get_screen_height());
+    let mouse = rl.get_screen_to_world2D(rl.get_mouse_position(), camera);
+    let mouse = Vec2::new(mouse.x, mouse.y);
+    let mouse_follow = state.mouse_follow;
+    if let Some(player) = state.players.get_mut(&player_id) {
+        player.vel = mouse_follow.velocity_toward(player.pos, mouse);
+        // vel is pixels per frame at FRAMES_PER_SECOND, scale it to how long this frame took
+        player.pos += player.vel * dt * FRAMES_PER_SECOND as f32;
+        state.camera.step(player.pos, player.vel, dt);
+    }
+}
+
+/// Announces a client to everyone else if they haven't been yet.
+/// Joins are held back until the client says something or the grace period runs out,
+/// so port scanners and failed handshakes never show up as a blob.
+async fn announce_join_if_pending(client_id: u32) {
+    if PENDING_JOINS.lock().unwrap().remove(&client_id).is_none() {
+        return;
+    }
+    let outbound_message = ServerToClientMessage::PlayerJoined { id: client_id };
+    broadcast_to_all_except(client_id, outbound_message).await;
+}
+
+/// Welcome, then the MOTD if the server has one.
+pub async fn send_welcome(client_id: u32, motd: Option<&str>) {
+    let outbound_message = ServerToClientMessage::Welcome {
+        server_message: "welcome to the server".to_string(),
+        server_name: SERVER_NAME.to_string(),
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    send_to_one_client(client_id, outbound_message).await;
+
+    if let Some(motd) = motd {
+        let outbound_message = ServerToClientMessage::SystemMessage {
+            message: motd.to_string(),
+        };
+        send_to_one_client(client_id, outbound_message).await;
+    }
 }
 
 pub async fn process_message_queue() {
+    let due: Vec<u32> = PENDING_JOINS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, connected_at)| connected_at.elapsed() >= JOIN_ANNOUNCE_GRACE)
+        .map(|(&id, _)| id)
+        .collect();
+    for client_id in due {
+        announce_join_if_pending(client_id).await;
+    }
+
     while let Some(message_bundle) = INCOMING_MESSAGE_QUEUE.pop() {
         let client_id = message_bundle.client_id;
+        match &message_bundle.message {
+            ClientToServerMessage::Connect | ClientToServerMessage::Disconnect => {}
+            _ => announce_join_if_pending(client_id).await,
+        }
         match message_bundle.message {
             ClientToServerMessage::Connect => {
                 println!("Client {} connected", client_id);
+                send_welcome(client_id, MOTD.as_deref()).await;
 
-                // send welcome
-                let outbound_message = ServerToClientMessage::Welcome {
-                    server_message: "welcome to the server".to_string(),
-                };
-                send_to_one_client(client_id, outbound_message).await;
-
-                // announce the join
-                let outbound_message = ServerToClientMessage::PlayerJoined { id: client_id };
-                broadcast_to_all_except(client_id, outbound_message).await;
+                // the join gets announced once they've proven they're a real client
+                PENDING_JOINS
+                    .lock()
+                    .unwrap()
+                    .insert(client_id, Instant::now());
             }
             ClientToServerMessage::Disconnect => {
                 println!("Client {} disconnected", client_id);
 
+                // nobody ever saw them, so nobody needs to see them leave
+                if PENDING_JOINS.lock().unwrap().remove(&client_id).is_some() {
+                    continue;
+                }
+
                 // announce the leave
                 let outbound_message = ServerToClientMessage::PlayerLeft { id: client_id };
                 broadcast_to_all_except(client_id, outbound_message).await;
@@ -60,6 +614,286 @@ pub async fn process_message_queue() {
                 };
                 broadcast_to_all_except(client_id, outbound_message).await;
             }
+            ClientToServerMessage::Relay { target, payload } => {
+                if payload.len() > MAX_RELAY_PAYLOAD_BYTES {
+                    eprintln!(
+                        "Client {} relay to {} too big: {} bytes",
+                        client_id,
+                        target,
+                        payload.len()
+                    );
+                    continue;
+                }
+                if target == client_id
+                    || !CLIENT_OUTBOUND_MAILBOXES.read().await.contains_key(&target)
+                {
+                    eprintln!("Client {} relay to unknown client {}", client_id, target);
+                    continue;
+                }
+
+                let outbound_message = ServerToClientMessage::Relayed {
+                    from: client_id,
+                    payload,
+                };
+                send_to_one_client(target, outbound_message).await;
+            }
+            ClientToServerMessage::Position { .. } => {
+                // nothing simulates positions server side yet,
+                // but it still counted as proof of life for the join above
+            }
+            ClientToServerMessage::Doodle { strokes } => {
+                // NaN fails the comparison too, so this also rejects non-finite points
+                let in_bounds = strokes
+                    .iter()
+                    .all(|(x, y)| x.abs() <= MAX_DOODLE_EXTENT && y.abs() <= MAX_DOODLE_EXTENT);
+                if strokes.len() > MAX_DOODLE_POINTS || !in_bounds {
+                    eprintln!(
+                        "Client {} sent a bad doodle: {} points",
+                        client_id,
+                        strokes.len()
+                    );
+                    continue;
+                }
+
+                let outbound_message = ServerToClientMessage::Doodle {
+                    id: client_id,
+                    strokes,
+                };
+                broadcast_to_all_except(client_id, outbound_message).await;
+            }
         }
     }
 }
+
+////////////////////////    CONSOLE    ////////////////////////
+
+/// Only local settings for now. The sketch client has no way to send anything
+/// but position updates, so there's nothing for name/team/ping style commands to map to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsoleCommand {
+    DeadZone(f32),
+    Sensitivity(f32),
+    /// None is uncapped.
+    TargetFps(Option<u32>),
+}
+
+pub fn parse_console_command(line: &str) -> Result<ConsoleCommand, String> {
+    let line = line.trim();
+    let line = line.strip_prefix('/').unwrap_or(line);
+    let (command, arg) = match line.split_once(' ') {
+        Some((command, arg)) => (command, arg.trim()),
+        None => (line, ""),
+    };
+    match command {
+        "deadzone" => match arg.parse::<f32>() {
+            Ok(dead_zone) if dead_zone >= 0.0 => Ok(ConsoleCommand::DeadZone(dead_zone)),
+            _ => Err(format!("bad dead zone: {}", arg)),
+        },
+        "sensitivity" => match arg.parse::<f32>() {
+            Ok(sensitivity) if sensitivity > 0.0 => Ok(ConsoleCommand::Sensitivity(sensitivity)),
+            _ => Err(format!("bad sensitivity: {}", arg)),
+        },
+        "fps" if arg == "off" => Ok(ConsoleCommand::TargetFps(None)),
+        "fps" => match arg.parse::<u32>() {
+            Ok(fps) if fps > 0 => Ok(ConsoleCommand::TargetFps(Some(fps))),
+            _ => Err(format!("bad fps: {}", arg)),
+        },
+        _ => Err(format!("unknown command: {}", line)),
+    }
+}
+
+pub fn apply_console_command(state: &mut ClientState, command: ConsoleCommand) {
+    match command {
+        ConsoleCommand::DeadZone(dead_zone) => state.mouse_follow.dead_zone = dead_zone,
+        ConsoleCommand::Sensitivity(sensitivity) => state.mouse_follow.sensitivity = sensitivity,
+        ConsoleCommand::TargetFps(target_fps) => state.target_fps = target_fps,
+    }
+}
+
+const CONSOLE_HISTORY_LINES: usize = 8;
+
+/// Toggled with backtick. Commands are handled locally, they never go out as chat.
+pub struct Console {
+    pub open: bool,
+    pub input: String,
+    pub history: VecDeque<String>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self {
+            open: false,
+            input: String::new(),
+            history: VecDeque::with_capacity(CONSOLE_HISTORY_LINES),
+        }
+    }
+
+    pub fn log(&mut self, line: String) {
+        if self.history.len() == CONSOLE_HISTORY_LINES {
+            self.history.pop_front();
+        }
+        self.history.push_back(line);
+    }
+
+    /// Eats keyboard input while open. Returns a command when one is submitted.
+    pub fn step(&mut self, rl: &mut RaylibHandle) -> Option<ConsoleCommand> {
+        if rl.is_key_pressed(KeyboardKey::KEY_GRAVE) {
+            self.open = !self.open;
+            self.input.clear();
+            return None;
+        }
+        if !self.open {
+            return None;
+        }
+
+        while let Some(c) = rl.get_char_pressed() {
+            if c != '`' {
+                self.input.push(c);
+            }
+        }
+        if rl.is_key_pressed(KeyboardKey::KEY_BACKSPACE) {
+            self.input.pop();
+        }
+        if !rl.is_key_pressed(KeyboardKey::KEY_ENTER) || self.input.is_empty() {
+            return None;
+        }
+
+        let line = std::mem::take(&mut self.input);
+        self.log(format!("> {}", line));
+        match parse_console_command(&line) {
+            Ok(command) => Some(command),
+            Err(e) => {
+                self.log(e);
+                None
+            }
+        }
+    }
+
+    pub fn draw(&self, d: &mut RaylibDrawHandle) {
+        if !self.open {
+            return;
+        }
+        let line_height = 12;
+        let height = line_height * (CONSOLE_HISTORY_LINES as i32 + 1) + 8;
+        d.draw_rectangle(0, 0, d.get_screen_width(), height, Color::new(0, 0, 0, 200));
+        for (i, line) in self.history.iter().enumerate() {
+            d.draw_text(line, 4, 4 + i as i32 * line_height, 10, Color::LIGHTGRAY);
+        }
+        let prompt = format!("> {}_", self.input);
+        let y = 4 + CONSOLE_HISTORY_LINES as i32 * line_height;
+        d.draw_text(&prompt, 4, y, 10, Color::WHITE);
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCREEN_SIZE: Vec2 = Vec2::new(800.0, 600.0);
+
+    fn player_at(id: u32, pos: Vec2) -> Player {
+        let mut player = Player::new(id);
+        player.pos = pos;
+        player
+    }
+
+    #[test]
+    fn two_thousand_players_are_culled_and_capped() {
+        let view_center = Vec2::new(1000.0, 1000.0);
+        let mut players = HashMap::new();
+        // 1500 packed onto the screen, 500 well off it
+        for id in 0..1500u32 {
+            let offset = Vec2::new(
+                (id % 50) as f32 * 16.0 - 400.0,
+                (id / 50) as f32 * 20.0 - 300.0,
+            );
+            players.insert(id, player_at(id, view_center + offset));
+        }
+        for id in 1500..2000u32 {
+            let offset = Vec2::new(5000.0 + id as f32, 0.0);
+            players.insert(id, player_at(id, view_center + offset));
+        }
+
+        let animations = PlayerAnimations::new();
+        let plan = plan_player_draws(&players, &animations, view_center, SCREEN_SIZE);
+
+        assert_eq!(plan.visible.len(), MAX_DRAWN_PLAYERS);
+        assert!(plan.visible.iter().all(|player| player.id < 1500));
+        assert!(plan.labeled > 0 && plan.labeled <= MAX_LABELED_PLAYERS);
+        let distances: Vec<f32> = plan
+            .visible
+            .iter()
+            .map(|player| player.pos.distance(view_center))
+            .collect();
+        assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn only_nearby_players_are_labeled() {
+        let view_center = Vec2::ZERO;
+        let players = HashMap::from([
+            (1, player_at(1, Vec2::new(10.0, 0.0))),
+            (2, player_at(2, Vec2::new(390.0, 0.0))),
+            (3, player_at(3, Vec2::new(-2000.0, 0.0))),
+        ]);
+
+        let animations = PlayerAnimations::new();
+        let plan = plan_player_draws(&players, &animations, view_center, SCREEN_SIZE);
+
+        let ids: Vec<u32> = plan.visible.iter().map(|player| player.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(plan.labeled, 1);
+    }
+
+    // a frame every 100ms for this long
+    fn run_quality(quality: &mut AdaptiveQuality, fps: u32, target_fps: Option<u32>, secs: u64) {
+        let start = Instant::now();
+        for tick in 0..=secs * 10 {
+            let now = start + Duration::from_millis(tick * 100);
+            quality.step_at(fps, target_fps, now);
+        }
+    }
+
+    #[test]
+    fn hitting_a_low_fps_cap_keeps_full_quality() {
+        let mut quality = AdaptiveQuality::new();
+        run_quality(&mut quality, 30, Some(30), 10);
+        assert_eq!(quality.current(), QualityLevel::Full);
+    }
+
+    #[test]
+    fn falling_well_short_of_the_cap_sheds_quality() {
+        let mut quality = AdaptiveQuality::new();
+        run_quality(&mut quality, 20, Some(30), 2);
+        assert!(quality.current() < QualityLevel::Full);
+
+        // and it comes back once we're hitting the cap again
+        let degraded = quality.current();
+        run_quality(&mut quality, 30, Some(30), 6);
+        assert!(quality.current() > degraded);
+    }
+
+    #[test]
+    fn uncapped_uses_the_fixed_thresholds() {
+        let mut quality = AdaptiveQuality::new();
+        run_quality(&mut quality, 50, None, 10);
+        assert_eq!(quality.current(), QualityLevel::Full);
+
+        run_quality(&mut quality, 40, None, 2);
+        assert!(quality.current() < QualityLevel::Full);
+    }
+
+    #[test]
+    fn override_pins_the_level() {
+        let mut quality = AdaptiveQuality::new();
+        quality.override_level = Some(QualityLevel::NoSprites);
+        run_quality(&mut quality, 5, Some(60), 5);
+        assert_eq!(quality.current(), QualityLevel::NoSprites);
+    }
+}
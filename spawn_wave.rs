@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant};
+
+use tokio::io::{self, AsyncReadExt};
+use tokio::net::TcpStream;
+
+// connects a crowd of clients in waves and reports how the join path held up
+// usage: spawn_wave [clients] [per_wave] [wave_interval_ms]
+// every client comes from this host, so start the server with the per ip cap raised,
+// e.g. MAX_CONNECTIONS_PER_IP=1000, or it measures the limiter instead
+
+const SERVER_ADDR: &str = "127.0.0.1:8080";
+const DEFAULT_CLIENTS: usize = 200;
+const DEFAULT_CLIENTS_PER_WAVE: usize = 10;
+const DEFAULT_WAVE_INTERVAL_MS: u64 = 100;
+// how long a client waits for its first message before we call it a failure
+const FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+// keep everyone connected for a bit so later joiners see a full server
+const HOLD_CONNECTION_FOR: Duration = Duration::from_secs(5);
+
+struct JoinTiming {
+    time_to_id: Duration,
+    time_to_first_message: Duration,
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let arg_or =
+        |i: usize, default: u64| args.get(i).and_then(|a| a.parse().ok()).unwrap_or(default);
+    let clients = arg_or(1, DEFAULT_CLIENTS as u64) as usize;
+    let per_wave = (arg_or(2, DEFAULT_CLIENTS_PER_WAVE as u64) as usize).max(1);
+    let wave_interval = Duration::from_millis(arg_or(3, DEFAULT_WAVE_INTERVAL_MS));
+
+    println!(
+        "Spawning {} clients, {} every {:?}",
+        clients, per_wave, wave_interval
+    );
+
+    let mut handles = Vec::with_capacity(clients);
+    for i in 0..clients {
+        if i > 0 && i % per_wave == 0 {
+            tokio::time::sleep(wave_interval).await;
+        }
+        handles.push(tokio::spawn(join_once()));
+    }
+
+    let mut timings = Vec::new();
+    let mut failures = 0;
+    for handle in handles {
+        match handle.await {
+            Ok(Ok(timing)) => timings.push(timing),
+            Ok(Err(e)) => {
+                eprintln!("Client failed to join: {}", e);
+                failures += 1;
+            }
+            Err(e) => {
+                eprintln!("Client task panicked: {:?}", e);
+                failures += 1;
+            }
+        }
+    }
+    report(clients, failures, &timings);
+}
+
+async fn join_once() -> io::Result<JoinTiming> {
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(SERVER_ADDR).await?;
+
+    let mut id_buffer = [0u8; 4];
+    stream.read_exact(&mut id_buffer).await?;
+    let time_to_id = started.elapsed();
+
+    let mut buffer = [0; 1024];
+    let nbytes = tokio::time::timeout(FIRST_MESSAGE_TIMEOUT, stream.read(&mut buffer))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no first message"))??;
+    if nbytes == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "server closed before first message",
+        ));
+    }
+    let time_to_first_message = started.elapsed();
+
+    tokio::time::sleep(HOLD_CONNECTION_FOR).await;
+    Ok(JoinTiming {
+        time_to_id,
+        time_to_first_message,
+    })
+}
+
+fn report(clients: usize, failures: usize, timings: &[JoinTiming]) {
+    let succeeded = clients - failures;
+    println!(
+        "{}/{} joined ({:.1}%)",
+        succeeded,
+        clients,
+        100.0 * succeeded as f32 / clients.max(1) as f32
+    );
+    if timings.is_empty() {
+        return;
+    }
+
+    let mut to_id: Vec<Duration> = timings.iter().map(|t| t.time_to_id).collect();
+    let mut to_first: Vec<Duration> = timings.iter().map(|t| t.time_to_first_message).collect();
+    to_id.sort();
+    to_first.sort();
+    let percentile = |sorted: &[Duration], p: f32| sorted[((sorted.len() - 1) as f32 * p) as usize];
+
+    println!(
+        "time to id:            p50 {:?}  p99 {:?}  max {:?}",
+        percentile(&to_id, 0.5),
+        percentile(&to_id, 0.99),
+        to_id[to_id.len() - 1]
+    );
+    println!(
+        "time to first message: p50 {:?}  p99 {:?}  max {:?}",
+        percentile(&to_first, 0.5),
+        percentile(&to_first, 0.99),
+        to_first[to_first.len() - 1]
+    );
+}
@@ -0,0 +1,24 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+lazy_static! {
+    pub static ref TRACE_MESSAGES: Arc<AtomicBool> =
+        Arc::new(AtomicBool::new(std::env::var("TRACE_MESSAGES").is_ok()));
+}
+
+/// Logs a message as single line json, whatever the wire encoding is.
+/// Nothing gets serialized unless tracing is switched on.
+/// `peer` is whatever identifies the other end, a client id or the udp client's uuid.
+pub fn trace_message<T: Serialize, P: Display>(direction: &str, peer: P, message: &T) {
+    if !TRACE_MESSAGES.load(Ordering::Relaxed) {
+        return;
+    }
+    match serde_json::to_string(message) {
+        Ok(json) => println!("[trace] {} {}: {}", direction, peer, json),
+        Err(e) => eprintln!("Error serializing message for trace: {:?}", e),
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crossbeam::queue::ArrayQueue;
+
+use crate::common::client_to_server::ClientToServerMessage;
+use crate::common::server_to_client::ServerToClientMessage;
+
+/// Anything a client can push messages into and pop messages out of, so the same
+/// client code runs over tcp (client_netcode::connect_transport) or straight through
+/// memory (see connect_in_process).
+pub trait Transport {
+    type Outbound;
+    type Inbound;
+
+    /// Queues a message to go out, false if it had to be dropped.
+    fn send(&self, message: Self::Outbound) -> bool;
+    fn try_recv(&self) -> Option<Self::Inbound>;
+    fn is_disconnected(&self) -> bool;
+}
+
+/// A pair of queues with the actual io happening somewhere else.
+pub struct QueueTransport<Out, In> {
+    pub outbound: Arc<ArrayQueue<Out>>,
+    pub incoming: Arc<ArrayQueue<In>>,
+    pub disconnected: Arc<AtomicBool>,
+}
+
+impl<Out, In> Transport for QueueTransport<Out, In> {
+    type Outbound = Out;
+    type Inbound = In;
+
+    fn send(&self, message: Out) -> bool {
+        self.outbound.push(message).is_ok()
+    }
+
+    fn try_recv(&self) -> Option<In> {
+        self.incoming.pop()
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::SeqCst)
+    }
+}
+
+pub type ClientTransport = QueueTransport<ClientToServerMessage, ServerToClientMessage>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue_transport(capacity: usize) -> QueueTransport<u32, u32> {
+        QueueTransport {
+            outbound: Arc::new(ArrayQueue::new(capacity)),
+            incoming: Arc::new(ArrayQueue::new(capacity)),
+            disconnected: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // written against the trait so any transport can be dropped in
+    fn send_all<T: Transport<Outbound = u32>>(transport: &T, count: u32) -> u32 {
+        (0..count).filter(|&i| transport.send(i)).count() as u32
+    }
+
+    fn recv_all<T: Transport<Inbound = u32>>(transport: &T) -> Vec<u32> {
+        std::iter::from_fn(|| transport.try_recv()).collect()
+    }
+
+    #[test]
+    fn sent_messages_come_out_the_other_side_in_order() {
+        let transport = queue_transport(8);
+        assert_eq!(send_all(&transport, 3), 3);
+        // the io side drains outbound and fills incoming
+        while let Some(message) = transport.outbound.pop() {
+            transport.incoming.push(message * 10).unwrap();
+        }
+        assert_eq!(recv_all(&transport), vec![0, 10, 20]);
+        assert_eq!(transport.try_recv(), None);
+    }
+
+    #[test]
+    fn send_reports_drops_when_full() {
+        let transport = queue_transport(2);
+        assert_eq!(send_all(&transport, 5), 2);
+    }
+
+    #[test]
+    fn disconnect_is_seen_through_the_trait() {
+        let transport = queue_transport(1);
+        assert!(!transport.is_disconnected());
+        transport.disconnected.store(true, Ordering::SeqCst);
+        assert!(transport.is_disconnected());
+    }
+}